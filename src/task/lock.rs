@@ -1,14 +1,17 @@
 // Copyright 2019 TiKV Project Authors. Licensed under Apache-2.0.
 
 use std::cell::UnsafeCell;
+use std::fmt;
+use std::mem::{self, MaybeUninit};
 use std::ops::{Deref, DerefMut};
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
 
 /// A simple spin lock for synchronization between Promise
 /// and future.
 pub struct SpinLock<T> {
     handle: UnsafeCell<T>,
     lock: AtomicBool,
+    poisoned: AtomicBool,
 }
 
 // It's a lock, as long as the content can be sent between
@@ -22,20 +25,122 @@ impl<T> SpinLock<T> {
         SpinLock {
             handle: UnsafeCell::new(t),
             lock: AtomicBool::new(false),
+            poisoned: AtomicBool::new(false),
         }
     }
 
-    pub fn lock(&self) -> LockGuard<'_, T> {
-        // TODO: what if poison?
-        // It's safe to use swap here. If previous is false, then the lock
-        // is taken, loop will break, set it to true is expected;
-        // If previous is true, then the loop will go on until others swap
-        // back a false, set it to true changes nothing.
-        while self.lock.swap(true, Ordering::SeqCst) {}
-        LockGuard { inner: self }
+    /// Acquire the lock, spinning until it's available.
+    ///
+    /// If a previous holder panicked while the lock was held, the lock
+    /// becomes poisoned and this returns `Err` carrying the guard, so a
+    /// caller that knows the protected data is still usable can recover
+    /// it via `PoisonError::into_inner`.
+    pub fn lock(&self) -> LockResult<LockGuard<'_, T>> {
+        // Test-and-test-and-set: only attempt the exclusive compare_exchange
+        // once a relaxed read suggests the lock is free, instead of swapping
+        // on every iteration, to cut down on cache-line bus traffic under
+        // contention. Back off with an escalating, doubling number of
+        // `spin_loop` hints, then fall back to yielding the thread once
+        // spinning for this long stops being productive.
+        const MAX_SPINS: u32 = 64;
+        let mut spins = 1;
+        while self.lock.load(Ordering::Relaxed)
+            || self
+                .lock
+                .compare_exchange_weak(false, true, Ordering::SeqCst, Ordering::Relaxed)
+                .is_err()
+        {
+            if spins < MAX_SPINS {
+                for _ in 0..spins {
+                    core::hint::spin_loop();
+                }
+                spins *= 2;
+            } else {
+                std::thread::yield_now();
+            }
+        }
+        let guard = LockGuard { inner: self };
+        if self.poisoned.load(Ordering::SeqCst) {
+            Err(PoisonError::new(guard))
+        } else {
+            Ok(guard)
+        }
+    }
+
+    /// Try to acquire the lock without spinning.
+    ///
+    /// Returns `None` immediately if the lock is currently held elsewhere,
+    /// instead of burning a thread waiting for it to free up. Like `lock`,
+    /// the acquisition itself is carried in a `LockResult`, so a poisoned
+    /// lock still surfaces `Err` instead of silently handing back a guard
+    /// onto possibly-inconsistent data.
+    pub fn try_lock(&self) -> Option<LockResult<LockGuard<'_, T>>> {
+        if self.lock.compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
+            let guard = LockGuard { inner: self };
+            if self.poisoned.load(Ordering::SeqCst) {
+                Some(Err(PoisonError::new(guard)))
+            } else {
+                Some(Ok(guard))
+            }
+        } else {
+            None
+        }
+    }
+
+    /// Whether a holder of this lock has panicked while the guard was live.
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned.load(Ordering::SeqCst)
+    }
+
+    /// Clear the poisoned state, e.g. after verifying the protected data
+    /// was left in a consistent state.
+    pub fn clear_poison(&self) {
+        self.poisoned.store(false, Ordering::SeqCst);
+    }
+}
+
+/// A type alias for the result of a lock acquisition that may observe
+/// poisoning left by a panicked holder.
+pub type LockResult<Guard> = Result<Guard, PoisonError<Guard>>;
+
+/// The error returned by `SpinLock::lock` when the lock is poisoned.
+///
+/// The guard is still carried by the error, since the protected data may
+/// be salvageable even though a peer panicked while holding it.
+pub struct PoisonError<Guard> {
+    guard: Guard,
+}
+
+impl<Guard> PoisonError<Guard> {
+    fn new(guard: Guard) -> PoisonError<Guard> {
+        PoisonError { guard }
+    }
+
+    /// Recover the guard that was carried by this error.
+    pub fn into_inner(self) -> Guard {
+        self.guard
+    }
+
+    /// Borrow the guard that was carried by this error.
+    pub fn get_ref(&self) -> &Guard {
+        &self.guard
     }
 }
 
+impl<Guard> fmt::Debug for PoisonError<Guard> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        "PoisonError { .. }".fmt(f)
+    }
+}
+
+impl<Guard> fmt::Display for PoisonError<Guard> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        "lock poisoned".fmt(f)
+    }
+}
+
+impl<Guard> std::error::Error for PoisonError<Guard> {}
+
 /// A guard for `SpinLock`.
 pub struct LockGuard<'a, T> {
     inner: &'a SpinLock<T>,
@@ -57,10 +162,211 @@ impl<'a, T> DerefMut for LockGuard<'a, T> {
 
 impl<'a, T> Drop for LockGuard<'a, T> {
     fn drop(&mut self) {
+        if std::thread::panicking() {
+            self.inner.poisoned.store(true, Ordering::SeqCst);
+        }
         self.inner.lock.swap(false, Ordering::SeqCst);
     }
 }
 
+/// State sentinel marking a `SpinRwLock` as exclusively held by a writer.
+const WRITER: usize = usize::MAX;
+
+/// A reader-writer spin lock for synchronization between Promise
+/// and future.
+///
+/// Unlike `SpinLock`, any number of readers may hold the lock at once,
+/// which gives read-heavy sharing paths far better throughput.
+pub struct SpinRwLock<T> {
+    handle: UnsafeCell<T>,
+    state: AtomicUsize,
+}
+
+// It's a lock, as long as the content can be sent between
+// threads, it's Sync and Send.
+unsafe impl<T: Send> Sync for SpinRwLock<T> {}
+unsafe impl<T: Send> Send for SpinRwLock<T> {}
+
+impl<T> SpinRwLock<T> {
+    /// Create a lock with the given value.
+    pub fn new(t: T) -> SpinRwLock<T> {
+        SpinRwLock {
+            handle: UnsafeCell::new(t),
+            state: AtomicUsize::new(0),
+        }
+    }
+
+    /// Acquire a shared read lock, spinning while a writer holds it.
+    pub fn read(&self) -> ReadGuard<'_, T> {
+        loop {
+            let state = self.state.load(Ordering::SeqCst);
+            if state == WRITER {
+                continue;
+            }
+            assert!(state < WRITER - 1, "SpinRwLock reader count overflow");
+            if self
+                .state
+                .compare_exchange(state, state + 1, Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok()
+            {
+                return ReadGuard { inner: self };
+            }
+        }
+    }
+
+    /// Acquire the exclusive write lock, spinning until no readers or
+    /// writer remain.
+    pub fn write(&self) -> WriteGuard<'_, T> {
+        while self
+            .state
+            .compare_exchange(0, WRITER, Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
+        {}
+        WriteGuard { inner: self }
+    }
+}
+
+/// A shared guard for `SpinRwLock`.
+pub struct ReadGuard<'a, T> {
+    inner: &'a SpinRwLock<T>,
+}
+
+impl<'a, T> Deref for ReadGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.inner.handle.get() }
+    }
+}
+
+impl<'a, T> Drop for ReadGuard<'a, T> {
+    fn drop(&mut self) {
+        self.inner.state.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// An exclusive guard for `SpinRwLock`.
+pub struct WriteGuard<'a, T> {
+    inner: &'a SpinRwLock<T>,
+}
+
+impl<'a, T> Deref for WriteGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.inner.handle.get() }
+    }
+}
+
+impl<'a, T> DerefMut for WriteGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.inner.handle.get() }
+    }
+}
+
+impl<'a, T> Drop for WriteGuard<'a, T> {
+    fn drop(&mut self) {
+        self.inner.state.swap(0, Ordering::SeqCst);
+    }
+}
+
+const INCOMPLETE: u8 = 0;
+const RUNNING: u8 = 1;
+const COMPLETE: u8 = 2;
+const POISONED: u8 = 3;
+
+/// Poisons a `SpinOnce`'s state on unwind unless disarmed with `mem::forget`
+/// after the initializer returns successfully.
+struct PoisonOnUnwind<'a>(&'a AtomicU8);
+
+impl<'a> Drop for PoisonOnUnwind<'a> {
+    fn drop(&mut self) {
+        self.0.store(POISONED, Ordering::SeqCst);
+    }
+}
+
+/// A spin-based lazy-initialization primitive.
+///
+/// This supersedes ad-hoc spin-lock-guarded `Option<T>` initialization:
+/// it's cheaper than pairing `std::sync::Once` with a separate cell, and
+/// `new` is a `const fn` so it can live directly in a `static`.
+pub struct SpinOnce<T> {
+    state: AtomicU8,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+// Unlike a lock, every caller gets a shared `&T` at the same time, so
+// it's Sync only if the content is both Send and Sync.
+unsafe impl<T: Send + Sync> Sync for SpinOnce<T> {}
+unsafe impl<T: Send> Send for SpinOnce<T> {}
+
+impl<T> SpinOnce<T> {
+    /// Create an uninitialized `SpinOnce`.
+    pub const fn new() -> SpinOnce<T> {
+        SpinOnce {
+            state: AtomicU8::new(INCOMPLETE),
+            value: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    /// Run `f` to initialize the value the first time this is called.
+    ///
+    /// Only the caller that wins the race runs `f`; concurrent callers
+    /// spin until the value is ready, and all of them return a reference
+    /// to the same shared value. If `f` panics, the `SpinOnce` is poisoned
+    /// and every later or waiting call panics as well.
+    pub fn call_once<F: FnOnce() -> T>(&self, f: F) -> &T {
+        if self
+            .state
+            .compare_exchange(INCOMPLETE, RUNNING, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+        {
+            // If `f` panics, this poisons the state instead of leaving it
+            // at RUNNING forever, which would otherwise spin-livelock every
+            // other caller. Mirrors `std::sync::Once`'s poisoning.
+            let poison_on_unwind = PoisonOnUnwind(&self.state);
+            let value = f();
+            mem::forget(poison_on_unwind);
+            unsafe { (*self.value.get()).write(value) };
+            self.state.store(COMPLETE, Ordering::SeqCst);
+        } else {
+            loop {
+                match self.state.load(Ordering::SeqCst) {
+                    COMPLETE => break,
+                    POISONED => panic!("SpinOnce instance has previously been poisoned"),
+                    _ => core::hint::spin_loop(),
+                }
+            }
+        }
+        unsafe { (*self.value.get()).assume_init_ref() }
+    }
+
+    /// Peek at the value without triggering initialization.
+    ///
+    /// Returns `None` if `call_once` hasn't completed yet.
+    pub fn get(&self) -> Option<&T> {
+        if self.state.load(Ordering::SeqCst) == COMPLETE {
+            Some(unsafe { (*self.value.get()).assume_init_ref() })
+        } else {
+            None
+        }
+    }
+}
+
+impl<T> Drop for SpinOnce<T> {
+    fn drop(&mut self) {
+        if *self.state.get_mut() == COMPLETE {
+            unsafe { (*self.value.get()).assume_init_drop() };
+        }
+    }
+}
+
+impl<T> Default for SpinOnce<T> {
+    fn default() -> SpinOnce<T> {
+        SpinOnce::new()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -74,9 +380,94 @@ mod tests {
         let lock1 = Arc::new(SpinLock::new(2));
         let lock2 = lock1.clone();
         let (tx, rx) = mpsc::channel();
-        let guard = lock1.lock();
+        let guard = lock1.lock().unwrap();
+        thread::spawn(move || {
+            let _guard = lock2.lock().unwrap();
+            tx.send(()).unwrap();
+        });
+        thread::sleep(Duration::from_millis(10));
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+        drop(guard);
+        assert_eq!(rx.recv(), Ok(()));
+    }
+
+    #[test]
+    fn test_lock_poisoning() {
+        let lock = Arc::new(SpinLock::new(2));
+        let lock2 = lock.clone();
+        assert!(!lock.is_poisoned());
+        let _ = thread::spawn(move || {
+            let _guard = lock2.lock().unwrap();
+            panic!("poison the lock");
+        })
+        .join();
+        assert!(lock.is_poisoned());
+        let guard = match lock.lock() {
+            Ok(_) => panic!("lock should be poisoned"),
+            Err(err) => err.into_inner(),
+        };
+        assert_eq!(*guard, 2);
+        drop(guard);
+        lock.clear_poison();
+        assert!(!lock.is_poisoned());
+        assert!(lock.lock().is_ok());
+    }
+
+    #[test]
+    fn test_try_lock() {
+        let lock = SpinLock::new(2);
+        let guard = lock.try_lock().unwrap().unwrap();
+        assert!(lock.try_lock().is_none());
+        drop(guard);
+        assert!(lock.try_lock().unwrap().is_ok());
+    }
+
+    #[test]
+    fn test_try_lock_poisoning() {
+        let lock = Arc::new(SpinLock::new(2));
+        let lock2 = lock.clone();
+        assert!(!lock.is_poisoned());
+        let _ = thread::spawn(move || {
+            let _guard = lock2.try_lock().unwrap().unwrap();
+            panic!("poison the lock");
+        })
+        .join();
+        assert!(lock.is_poisoned());
+        let guard = match lock.try_lock().unwrap() {
+            Ok(_) => panic!("lock should be poisoned"),
+            Err(err) => err.into_inner(),
+        };
+        assert_eq!(*guard, 2);
+        drop(guard);
+
+        let guard = match lock.lock() {
+            Ok(_) => panic!("lock should be poisoned"),
+            Err(err) => err.into_inner(),
+        };
+        drop(guard);
+    }
+
+    #[test]
+    fn test_rw_lock_readers_share() {
+        let lock1 = Arc::new(SpinRwLock::new(2));
+        let lock2 = lock1.clone();
+        let (tx, rx) = mpsc::channel();
+        let _guard1 = lock1.read();
+        thread::spawn(move || {
+            let _guard2 = lock2.read();
+            tx.send(()).unwrap();
+        });
+        assert_eq!(rx.recv_timeout(Duration::from_millis(100)), Ok(()));
+    }
+
+    #[test]
+    fn test_rw_lock_writer_excludes() {
+        let lock1 = Arc::new(SpinRwLock::new(2));
+        let lock2 = lock1.clone();
+        let (tx, rx) = mpsc::channel();
+        let guard = lock1.write();
         thread::spawn(move || {
-            let _guard = lock2.lock();
+            let _guard = lock2.read();
             tx.send(()).unwrap();
         });
         thread::sleep(Duration::from_millis(10));
@@ -84,4 +475,52 @@ mod tests {
         drop(guard);
         assert_eq!(rx.recv(), Ok(()));
     }
+
+    #[test]
+    fn test_once() {
+        let once = SpinOnce::new();
+        assert!(once.get().is_none());
+        assert_eq!(*once.call_once(|| 1), 1);
+        // `f` only runs once; later calls observe the first value.
+        assert_eq!(*once.call_once(|| 2), 1);
+        assert_eq!(once.get(), Some(&1));
+    }
+
+    #[test]
+    fn test_once_concurrent() {
+        let once = Arc::new(SpinOnce::new());
+        let (tx, rx) = mpsc::channel();
+        let mut threads = Vec::new();
+        for _ in 0..4 {
+            let once = once.clone();
+            let tx = tx.clone();
+            threads.push(thread::spawn(move || {
+                tx.send(*once.call_once(|| 7)).unwrap();
+            }));
+        }
+        for t in threads {
+            t.join().unwrap();
+        }
+        for _ in 0..4 {
+            assert_eq!(rx.recv(), Ok(7));
+        }
+    }
+
+    #[test]
+    fn test_once_poisons_on_panic() {
+        let once = Arc::new(SpinOnce::<i32>::new());
+        let once2 = once.clone();
+        let result = thread::spawn(move || {
+            once2.call_once(|| panic!("boom"));
+        })
+        .join();
+        assert!(result.is_err());
+
+        let once3 = once.clone();
+        let result = thread::spawn(move || {
+            once3.call_once(|| 1);
+        })
+        .join();
+        assert!(result.is_err(), "later callers must panic, not spin forever");
+    }
 }